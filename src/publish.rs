@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 use anyhow::Result;
 use bpaf::Bpaf;
@@ -10,11 +10,31 @@ use crate::cargo_command::CargoCommand;
 pub struct Options {
     #[bpaf(positional("PATH"), fallback(PathBuf::from(".")))]
     path: PathBuf,
+    /// How long to wait for a published crate to propagate to the registry
+    /// index before publishing the crates that depend on it, in seconds.
+    #[bpaf(long, argument("SECS"), fallback(300))]
+    propagation_timeout: u64,
+    /// Run the pre-flight checks (registry token, crate metadata and
+    /// license, release order, `cargo check`) and print the computed publish order, without publishing
+    /// anything.
+    #[bpaf(long("verify-only"))]
+    verify_only: bool,
+    /// SPDX license expressions that published crates are allowed to use.
+    /// Defaults to the permissive licenses oxc publishes under.
+    #[bpaf(long("allowed-license"), argument("SPDX"), fallback(default_allowed_licenses()))]
+    allowed_licenses: Vec<String>,
+}
+
+fn default_allowed_licenses() -> Vec<String> {
+    ["MIT", "Apache-2.0", "MIT OR Apache-2.0"].into_iter().map(String::from).collect()
 }
 
 pub struct Publish {
     metadata: Metadata,
     cargo: CargoCommand,
+    propagation_timeout: Duration,
+    verify_only: bool,
+    allowed_licenses: Vec<String>,
 }
 
 impl Publish {
@@ -23,23 +43,62 @@ impl Publish {
     pub fn new(options: Options) -> Result<Self> {
         let metadata = MetadataCommand::new().current_dir(&options.path).no_deps().exec()?;
         let cargo = CargoCommand::new(metadata.workspace_root.clone().into_std_path_buf());
-        Ok(Self { metadata, cargo })
+        let propagation_timeout = Duration::from_secs(options.propagation_timeout);
+        Ok(Self {
+            metadata,
+            cargo,
+            propagation_timeout,
+            verify_only: options.verify_only,
+            // A repeated argument parses to an empty list when the flag is
+            // never given, so the default has to be applied here.
+            allowed_licenses: if options.allowed_licenses.is_empty() {
+                default_allowed_licenses()
+            } else {
+                options.allowed_licenses
+            },
+        })
     }
 
     /// # Errors
     pub fn run(self) -> Result<()> {
+        anyhow::ensure!(
+            std::env::var("CARGO_REGISTRY_TOKEN").is_ok_and(|token| !token.is_empty()),
+            "CARGO_REGISTRY_TOKEN must be set to a non-empty value"
+        );
+
         let packages = self.get_packages();
+        metadata_check::check(&packages, &self.allowed_licenses)?;
         let packages = release_order::release_order(&packages)?;
-        let packages = packages.into_iter().map(|package| &package.name).collect::<Vec<_>>();
 
         println!("Checking");
         self.cargo.run(&["check", "--all-features", "--all-targets"])?;
 
-        println!("Publishing packages: {packages:?}");
+        let names = packages.iter().map(|p| &p.name).collect::<Vec<_>>();
+        if self.verify_only {
+            println!("Verify OK, publish order: {names:?}");
+            return Ok(());
+        }
+
+        println!("Publishing packages: {names:?}");
+
+        let mut published = vec![];
+        let mut skipped = vec![];
         for package in &packages {
-            self.cargo.publish(package)?;
+            if registry::version_exists(&package.name, &package.version.to_string())? {
+                skipped.push(&package.name);
+                continue;
+            }
+            self.cargo.publish(&package.name)?;
+            registry::wait_until_visible(
+                &package.name,
+                &package.version.to_string(),
+                self.propagation_timeout,
+            )?;
+            published.push(&package.name);
         }
-        println!("Published packages: {packages:?}");
+
+        println!("Skipped (already on the registry): {skipped:?}");
+        println!("Published packages: {published:?}");
         Ok(())
     }
 
@@ -50,60 +109,426 @@ impl Publish {
 }
 
 mod release_order {
+    use std::collections::HashMap;
+
     use anyhow::Result;
-    use cargo_metadata::Package;
+    use cargo_metadata::{DependencyKind, Package};
+    use petgraph::{
+        algo::{tarjan_scc, toposort},
+        graph::{DiGraph, NodeIndex},
+    };
 
     /// Return packages in an order they can be released.
     /// In the result, the packages are placed after all their dependencies.
-    /// Return an error if a circular dependency is detected.
+    /// Return an error reporting every cycle in the (normal + build)
+    /// dependency graph if it isn't a DAG.
     pub fn release_order<'a>(packages: &'a [&Package]) -> Result<Vec<&'a Package>> {
-        let mut order = vec![];
-        let mut passed = vec![];
-        for p in packages {
-            release_order_inner(packages, p, &mut order, &mut passed)?;
+        let graph = build_graph(packages);
+
+        toposort(&graph, None)
+            .map(|order| order.into_iter().map(|i| graph[i]).collect())
+            .map_err(|_| anyhow::anyhow!(describe_cycles(&graph)))
+    }
+
+    /// Build a graph with an edge from each dependency to its dependent, so
+    /// that a topological sort yields dependencies before the packages that
+    /// need them.
+    fn build_graph<'a>(packages: &[&'a Package]) -> DiGraph<&'a Package, ()> {
+        let mut graph = DiGraph::new();
+        let node_of: HashMap<&str, NodeIndex> =
+            packages.iter().map(|&p| (p.name.as_str(), graph.add_node(p))).collect();
+
+        for &pkg in packages {
+            for dep in &pkg.dependencies {
+                // Dev-dependencies are stripped from the published manifest, so
+                // they impose no ordering constraint and must not be part of
+                // the cycle check.
+                if !matches!(dep.kind, DependencyKind::Normal | DependencyKind::Build) {
+                    continue;
+                }
+                if dep.name == pkg.name {
+                    continue;
+                }
+                if let Some(&dep_node) = node_of.get(dep.name.as_str()) {
+                    graph.add_edge(dep_node, node_of[pkg.name.as_str()], ());
+                }
+            }
         }
-        Ok(order)
+
+        graph
     }
 
-    /// The `passed` argument is used to track packages that you already visited to
-    /// detect circular dependencies.
-    fn release_order_inner<'a>(
-        packages: &[&'a Package],
-        pkg: &'a Package,
-        order: &mut Vec<&'a Package>,
-        passed: &mut Vec<&'a Package>,
-    ) -> Result<()> {
-        if is_package_in(pkg, order) {
-            return Ok(());
+    /// Report every strongly connected component of size greater than one
+    /// (i.e. every genuine cycle) as the complete list of crates forming the
+    /// loop, instead of just the first back-edge found.
+    fn describe_cycles(graph: &DiGraph<&Package, ()>) -> String {
+        let cycles = tarjan_scc(graph)
+            .into_iter()
+            .filter(|scc| scc.len() > 1)
+            .map(|scc| scc.iter().map(|&i| graph[i].name.clone()).collect::<Vec<_>>().join(" -> "))
+            .collect::<Vec<_>>();
+
+        format!(
+            "Circular dependencies detected:\n{}",
+            cycles.iter().map(|cycle| format!("  {cycle}")).collect::<Vec<_>>().join("\n")
+        )
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use serde_json::json;
+
+        use super::*;
+
+        /// Build a `cargo_metadata::Dependency` fixture. `kind` is the
+        /// serialized form cargo uses: `"normal"`, `"build"`, or `"dev"`.
+        fn dep(name: &str, kind: &str) -> serde_json::Value {
+            json!({
+                "name": name,
+                "source": null,
+                "req": "*",
+                "kind": kind,
+                "optional": false,
+                "uses_default_features": true,
+                "features": [],
+                "target": null,
+                "rename": null,
+                "registry": null,
+            })
         }
-        passed.push(pkg);
-
-        for d in &pkg.dependencies {
-            // Check if the dependency is part of the packages we are releasing.
-            if let Some(dep) = packages.iter().find(|p| {
-                d.name == p.name
-              // Exclude the current package.
-              && p.name != pkg.name
-            }) {
-                anyhow::ensure!(
-                    !is_package_in(dep, passed),
-                    "Circular dependency detected: {} -> {}",
-                    dep.name,
-                    pkg.name,
-                );
-                release_order_inner(packages, dep, order, passed)?;
-            }
+
+        /// Build a `cargo_metadata::Package` fixture with the given name and
+        /// dependencies; the rest of the fields are filled with placeholders.
+        fn pkg(name: &str, deps: Vec<serde_json::Value>) -> Package {
+            serde_json::from_value(json!({
+                "name": name,
+                "version": "0.1.0",
+                "id": format!("{name} 0.1.0 (path+file:///fake/{name})"),
+                "license": null,
+                "license_file": null,
+                "description": null,
+                "source": null,
+                "dependencies": deps,
+                "targets": [],
+                "features": {},
+                "manifest_path": format!("/fake/{name}/Cargo.toml"),
+                "categories": [],
+                "keywords": [],
+                "readme": null,
+                "repository": null,
+                "homepage": null,
+                "documentation": null,
+                "edition": "2021",
+                "links": null,
+                "default_run": null,
+                "rust_version": null,
+                "publish": null,
+                "metadata": null,
+                "authors": [],
+            }))
+            .expect("package fixture should deserialize")
+        }
+
+        #[test]
+        fn orders_packages_after_their_dependencies() {
+            let a = pkg("a", vec![]);
+            let b = pkg("b", vec![dep("a", "normal")]);
+            let order = release_order(&[&a, &b]).unwrap();
+            assert_eq!(order.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        }
+
+        #[test]
+        fn reports_a_single_cycle() {
+            let a = pkg("a", vec![dep("b", "normal")]);
+            let b = pkg("b", vec![dep("a", "build")]);
+            let err = release_order(&[&a, &b]).unwrap_err().to_string();
+            assert!(err.contains("a -> b") || err.contains("b -> a"));
+        }
+
+        #[test]
+        fn reports_every_disjoint_cycle_in_one_error() {
+            let a = pkg("a", vec![dep("b", "normal")]);
+            let b = pkg("b", vec![dep("a", "normal")]);
+            let c = pkg("c", vec![dep("d", "normal")]);
+            let d = pkg("d", vec![dep("c", "normal")]);
+            let err = release_order(&[&a, &b, &c, &d]).unwrap_err().to_string();
+
+            // One header line plus one line per cycle: both cycles must be
+            // reported together, not just the first one found.
+            assert_eq!(err.lines().count(), 3);
+            assert!(err.contains('a') && err.contains('b'));
+            assert!(err.contains('c') && err.contains('d'));
+        }
+
+        #[test]
+        fn allows_dev_dependency_only_cycles() {
+            let a = pkg("a", vec![dep("b", "dev")]);
+            let b = pkg("b", vec![dep("a", "dev")]);
+            assert!(release_order(&[&a, &b]).is_ok());
+        }
+
+        #[test]
+        fn excludes_self_dependencies() {
+            let a = pkg("a", vec![dep("a", "normal")]);
+            assert!(release_order(&[&a]).is_ok());
         }
+    }
+}
+
+mod metadata_check {
+    use anyhow::Result;
+    use cargo_metadata::Package;
+
+    /// Validate that every package has the metadata crates.io requires for a
+    /// complete publish, and that its license is on the allow-list.
+    /// Violations are collected across the whole workspace and reported
+    /// together, rather than failing on the first package found.
+    ///
+    /// # Errors
+    /// Returns an error listing every violation if any package is missing
+    /// required metadata or uses a license outside `allowed_licenses`.
+    pub fn check(packages: &[&Package], allowed_licenses: &[String]) -> Result<()> {
+        let violations =
+            packages.iter().flat_map(|p| violations_for(p, allowed_licenses)).collect::<Vec<_>>();
 
-        order.push(pkg);
-        passed.clear();
+        anyhow::ensure!(
+            violations.is_empty(),
+            "Found {} metadata violation(s):\n{}",
+            violations.len(),
+            violations.join("\n")
+        );
         Ok(())
     }
 
-    /// Return true if the package is part of a packages array.
-    /// This function exists because `package.contains(pkg)` is expensive,
-    /// because it compares the whole package struct.
-    fn is_package_in(pkg: &Package, packages: &[&Package]) -> bool {
-        packages.iter().any(|p| p.name == pkg.name)
+    fn violations_for(package: &Package, allowed_licenses: &[String]) -> Vec<String> {
+        let mut violations = vec![];
+
+        if package.description.as_ref().is_none_or(|d| d.is_empty()) {
+            violations.push(format!("{}: missing `description`", package.name));
+        }
+        if package.repository.as_ref().is_none_or(|r| r.is_empty()) {
+            violations.push(format!("{}: missing `repository`", package.name));
+        }
+        match (&package.license, &package.license_file) {
+            (None, None) => violations.push(format!("{}: missing `license`", package.name)),
+            // `license-file` is a custom license text we can't check against
+            // the SPDX allow-list, so presence alone satisfies the gate.
+            (None, Some(_)) => {}
+            (Some(license), _) if !allowed_licenses.iter().any(|allowed| allowed == license) => {
+                violations.push(format!(
+                    "{}: license `{license}` is not in the allowed list {allowed_licenses:?}",
+                    package.name
+                ));
+            }
+            (Some(_), _) => {}
+        }
+
+        violations
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use serde_json::json;
+
+        use super::*;
+
+        /// Build a `cargo_metadata::Package` fixture with the given metadata
+        /// fields; the rest are filled with placeholders.
+        fn pkg(
+            name: &str,
+            description: Option<&str>,
+            repository: Option<&str>,
+            license: Option<&str>,
+            license_file: Option<&str>,
+        ) -> Package {
+            serde_json::from_value(json!({
+                "name": name,
+                "version": "0.1.0",
+                "id": format!("{name} 0.1.0 (path+file:///fake/{name})"),
+                "license": license,
+                "license_file": license_file,
+                "description": description,
+                "source": null,
+                "dependencies": [],
+                "targets": [],
+                "features": {},
+                "manifest_path": format!("/fake/{name}/Cargo.toml"),
+                "categories": [],
+                "keywords": [],
+                "readme": null,
+                "repository": repository,
+                "homepage": null,
+                "documentation": null,
+                "edition": "2021",
+                "links": null,
+                "default_run": null,
+                "rust_version": null,
+                "publish": null,
+                "metadata": null,
+                "authors": [],
+            }))
+            .expect("package fixture should deserialize")
+        }
+
+        const ALLOWED: &[&str] = &["MIT", "Apache-2.0", "MIT OR Apache-2.0"];
+
+        fn allowed() -> Vec<String> {
+            ALLOWED.iter().map(|s| (*s).to_string()).collect()
+        }
+
+        #[test]
+        fn passes_when_all_fields_are_valid() {
+            let a = pkg("a", Some("desc"), Some("https://example.com"), Some("MIT"), None);
+            assert!(check(&[&a], &allowed()).is_ok());
+        }
+
+        #[test]
+        fn flags_missing_description() {
+            let a = pkg("a", None, Some("https://example.com"), Some("MIT"), None);
+            let err = check(&[&a], &allowed()).unwrap_err().to_string();
+            assert!(err.contains("a: missing `description`"));
+        }
+
+        #[test]
+        fn flags_missing_repository() {
+            let a = pkg("a", Some("desc"), None, Some("MIT"), None);
+            let err = check(&[&a], &allowed()).unwrap_err().to_string();
+            assert!(err.contains("a: missing `repository`"));
+        }
+
+        #[test]
+        fn flags_missing_license_and_license_file() {
+            let a = pkg("a", Some("desc"), Some("https://example.com"), None, None);
+            let err = check(&[&a], &allowed()).unwrap_err().to_string();
+            assert!(err.contains("a: missing `license`"));
+        }
+
+        #[test]
+        fn exempts_license_file_without_license() {
+            let a =
+                pkg("a", Some("desc"), Some("https://example.com"), None, Some("LICENSE.txt"));
+            assert!(check(&[&a], &allowed()).is_ok());
+        }
+
+        #[test]
+        fn flags_disallowed_license() {
+            let a = pkg("a", Some("desc"), Some("https://example.com"), Some("GPL-3.0"), None);
+            let err = check(&[&a], &allowed()).unwrap_err().to_string();
+            assert!(err.contains("a: license `GPL-3.0` is not in the allowed list"));
+        }
+
+        #[test]
+        fn collects_violations_across_the_whole_workspace() {
+            let a = pkg("a", None, Some("https://example.com"), Some("MIT"), None);
+            let b = pkg("b", Some("desc"), None, Some("GPL-3.0"), None);
+            let err = check(&[&a, &b], &allowed()).unwrap_err().to_string();
+            assert!(err.contains("a: missing `description`"));
+            assert!(err.contains("b: missing `repository`"));
+            assert!(err.contains("b: license `GPL-3.0` is not in the allowed list"));
+            assert!(err.starts_with("Found 3 metadata violation(s)"));
+        }
+    }
+}
+
+mod registry {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Result;
+    use serde::Deserialize;
+
+    /// Initial delay between propagation polls, doubled after every attempt.
+    const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+    /// Upper bound on the delay between propagation polls.
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    /// One line of a crates.io sparse-index file.
+    /// The index has more fields than this (`deps`, `cksum`, `features`, ...)
+    /// but we only care about which versions exist.
+    #[derive(Debug, Deserialize)]
+    struct IndexRecord {
+        vers: String,
+    }
+
+    /// Return `true` if `name@version` already exists on the sparse index.
+    ///
+    /// # Errors
+    /// Returns an error if the index can't be reached for a reason other than
+    /// the crate not existing yet (a fresh crate 404s, which is not an error).
+    pub fn version_exists(name: &str, version: &str) -> Result<bool> {
+        let url =
+            format!("https://index.crates.io/{}/{}", index_prefix(name), name.to_lowercase());
+        let response = ureq::get(&url).call();
+        let body = match response {
+            Ok(response) => response.into_string()?,
+            Err(ureq::Error::Status(404, _)) => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(body
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_str::<IndexRecord>)
+            .collect::<Result<Vec<_>, _>>()?
+            .iter()
+            .any(|record| record.vers == version))
+    }
+
+    /// Poll the sparse index until `name@version` becomes visible, so that
+    /// crates depending on it can be published without racing propagation.
+    ///
+    /// # Errors
+    /// Returns an error if the index can't be reached, or if `timeout`
+    /// elapses before the new version appears.
+    pub fn wait_until_visible(name: &str, version: &str, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            if version_exists(name, version)? {
+                return Ok(());
+            }
+            anyhow::ensure!(
+                Instant::now() < deadline,
+                "Timed out waiting for {name}@{version} to appear on the registry index"
+            );
+            thread::sleep(backoff.min(deadline.saturating_duration_since(Instant::now())));
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Compute the sparse-index path prefix for a crate name, following the
+    /// same bucketing cargo itself uses:
+    /// <https://doc.rust-lang.org/cargo/reference/registry-index.html#index-files>
+    fn index_prefix(name: &str) -> String {
+        let lower = name.to_lowercase();
+        match lower.len() {
+            1 => "1".to_string(),
+            2 => "2".to_string(),
+            3 => format!("3/{}", &lower[..1]),
+            _ => format!("{}/{}", &lower[..2], &lower[2..4]),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn buckets_by_name_length() {
+            assert_eq!(index_prefix("a"), "1");
+            assert_eq!(index_prefix("ab"), "2");
+            assert_eq!(index_prefix("abc"), "3/a");
+            assert_eq!(index_prefix("abcd"), "ab/cd");
+            assert_eq!(index_prefix("abcde"), "ab/cd");
+        }
+
+        #[test]
+        fn lowercases_mixed_case_names() {
+            assert_eq!(index_prefix("A"), "1");
+            assert_eq!(index_prefix("ABC"), "3/a");
+            assert_eq!(index_prefix("SerDe"), "se/rd");
+        }
     }
 }